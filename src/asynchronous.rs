@@ -0,0 +1,333 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use log::{debug, warn, trace};
+
+use crate::state::CircuitState;
+use crate::recovery::RecoveryState;
+use crate::backoff::Backoff;
+use crate::CircuitBreakerError;
+
+/// The outcome of running a wrapped future through `AsyncThresholdBreaker::run`,
+/// distinguishing a timeout (no `E` value is available) from a regular `Err(E)`.
+enum Outcome<R, E> {
+    Success(R),
+    Failure(E),
+    TimedOut
+}
+
+/// The mutable state of an AsyncThresholdBreaker, guarded by a Mutex so the breaker can be
+/// shared (e.g. wrapped in an `Arc`) across many concurrent tasks without requiring `&mut self`.
+struct State {
+    /// The current count of failures. Will be resetted by success.
+    failure_count: usize,
+    /// The shared Open/Recovering/trip/reset state machine.
+    recovery: RecoveryState
+}
+
+///
+/// Async-aware counterpart of [`crate::CircuitBreaker`], operating on futures instead of
+/// plain closures, so protecting actual async I/O (the distributed-service use case this
+/// crate is meant for) no longer forces blocking. Because breakers of this kind are
+/// typically shared across tasks, `call` only needs `&self`; implementors guard their
+/// mutable state internally.
+///
+/// `call` is written as a desugared `-> impl Future<...> + Send` instead of a native
+/// `async fn`, since the latter can't express a `Send` bound on a public trait method and
+/// would leave the returned future un-spawnable (e.g. via `tokio::spawn`) by callers who
+/// share the breaker across tasks, exactly the use case this trait exists for.
+///
+pub trait AsyncCircuitBreaker<R: Send, E: Error + Send> {
+    /// Try to execute the future produced by `f` and count the failures here.
+    /// Any error returned by the embedded future will be propagated to the callee.
+    /// In addition a CircuitBreakerError might be thrown.
+    fn call<F, Fut>(&self, f: F) -> impl Future<Output = Result<R, CircuitBreakerError<E>>> + Send
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send;
+}
+
+///
+/// Async, thread-safe counterpart of ThresholdBreaker. The mutable state is guarded by a
+/// Mutex, so a single instance, typically wrapped in an `Arc`, can front many concurrent
+/// calls made from different tasks.
+///
+/// The Open-state gating, backoff and transition hook are shared with `ThresholdBreaker`
+/// via `RecoveryState`; only the failure counting and the Mutex-guarded access are specific
+/// to this breaker. `AsyncThresholdBreaker` is constructed with a zero recovery ramp, so
+/// Recovering behaves like a classic single-probe HalfOpen: the first call after the
+/// backoff elapses is admitted, a success closes the breaker, a failure trips it straight
+/// back to Open. Calls that arrive from other tasks while that first call is still running
+/// find the breaker Recovering and are admitted as well. Sharing `RecoveryState` also means the Open -> Recovering transition is
+/// guarded by the same vetted single-lock compare-and-swap used by `ThresholdBreaker` and
+/// `RateBreaker`, instead of this breaker re-deriving its own locking discipline.
+///
+pub struct AsyncThresholdBreaker {
+    /// The name of this breaker to better identify it in the locks.
+    name: String,
+    /// number of exapted failures
+    threshold: usize,
+    /// Optional deadline for the wrapped call. A call running longer than this is recorded
+    /// as a failure towards the trip threshold and surfaced as `CircuitBreakerError::Timeout`.
+    call_timeout: Option<Duration>,
+    /// The mutable state, guarded so the breaker can be shared across tasks.
+    state: Mutex<State>
+}
+impl <R: Send, E: Error + Send> AsyncCircuitBreaker <R, E> for AsyncThresholdBreaker
+{
+    // Desugared from a native `async fn` so the returned future can carry a `Send` bound;
+    // see the trait doc comment.
+    #[allow(clippy::manual_async_fn)]
+    fn call<F, Fut>(&self, f: F) -> impl Future<Output = Result<R, CircuitBreakerError<E>>> + Send
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send
+    {
+        async move {
+            debug!("[AsyncThresholdBreaker::call({})]", self.name);
+            match self.with_state(|state| state.recovery.status()) {
+                CircuitState::Open => self.handle_open(f).await,
+                CircuitState::Close => self.handle_close(f).await,
+                CircuitState::Recovering => self.handle_recovering(f).await,
+                // RecoveryState never hands this breaker a bare HalfOpen; treat it like
+                // Open defensively, since `try_leave_open` only ever moves to Recovering.
+                CircuitState::HalfOpen => self.handle_open(f).await
+            }
+        }
+    }
+}
+impl AsyncThresholdBreaker
+{
+    /// Creates a new AsyncThresholdBreaker instance.
+    /// @param name The name of the circuite breaker, for logging/debugging purposes.
+    /// @param threshold The number of consecutive failures, which trip the circuit breaker.
+    /// @param timeout The time before the circuit breaker isn't changing back to the close status.
+    /// @param call_timeout The deadline for the wrapped call. `None` disables it, in which
+    /// case calls can run arbitrarily long.
+    pub fn new(
+        name: &str,
+        threshold: Option<usize>,
+        timeout: Option<Duration>,
+        call_timeout: Option<Duration>) -> AsyncThresholdBreaker
+    {
+        debug!("[AsyncThresholdBreaker::new({})]", name);
+
+        let timeout = timeout.unwrap_or(Duration::new(5, 0));
+        AsyncThresholdBreaker {
+            name: String::from(name),
+            threshold: threshold.unwrap_or(5),
+            call_timeout,
+            state: Mutex::new(State {
+                failure_count: 0,
+                recovery: RecoveryState::new(name, Backoff::Constant(timeout), Duration::ZERO)
+            })
+        }
+    }
+
+    /// Runs `f` against the guarded state and returns its result. The lock is never held
+    /// across an `.await`, so the future produced by `f` stays `Send`.
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        let mut state = self.state.lock().expect("AsyncThresholdBreaker state lock poisoned");
+        f(&mut state)
+    }
+
+    /// Runs the future produced by `f`, bounding it by `call_timeout` if one is configured.
+    async fn run<F, Fut, R: Send, E: Send>(&self, f: F) -> Outcome<R, E>
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send
+    {
+        match self.call_timeout {
+            None => match f().await {
+                Ok(result) => Outcome::Success(result),
+                Err(error) => Outcome::Failure(error)
+            },
+            Some(timeout) => match tokio::time::timeout(timeout, f()).await {
+                Ok(Ok(result)) => Outcome::Success(result),
+                Ok(Err(error)) => Outcome::Failure(error),
+                Err(_) => Outcome::TimedOut
+            }
+        }
+    }
+
+    /// Handle the case if the circuit is open (tripped).
+    /// It just checks, if the time is up. If not, it just returns an CircuitBreakerError.
+    /// Moves to Recovering and calling the handler otherwise.
+    async fn handle_open<F, Fut, R: Send, E: Error + Send>(&self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send
+    {
+        debug!("[AsyncThresholdBreaker::handle_open({})]", self.name);
+        let now = SystemTime::now();
+        let won_swap = self.with_state(|state| state.recovery.try_leave_open(now));
+        if won_swap {
+            self.handle_recovering(f).await
+        }
+        else {
+            debug!("[AsyncThresholdBreaker::handle_open({})] stays open!", self.name);
+            Err(CircuitBreakerError::StaysOpen(String::from(&self.name)))
+        }
+    }
+
+    /// Handle the case, if the circuit is (still) closed.
+    /// In this case it tries to execute the future with the provided parameters.
+    /// If this fails, it will increase the failure counter, if the threshold reached,
+    /// it will trip().
+    async fn handle_close<F, Fut, R: Send, E: Error + Send>(&self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send
+    {
+        debug!("[AsyncThresholdBreaker::handle_close({})]", self.name);
+        match self.run(f).await {
+            Outcome::Success(result) => {
+                trace!("[AsyncThresholdBreaker::handle_close({})] Function called succssfully.", self.name);
+                self.reset();
+                Ok(result)
+            },
+            Outcome::Failure(error) => {
+                let failure_count = self.with_state(|state| {
+                    state.failure_count += 1;
+                    state.failure_count
+                });
+                warn!("[AsyncThresholdBreaker::handle_close({})] Function call failed {} times.",
+                    self.name, failure_count);
+                if failure_count > self.threshold {
+                    return self.trip(error);
+                }
+                Err(CircuitBreakerError::Failed(error))
+            },
+            Outcome::TimedOut => {
+                let failure_count = self.with_state(|state| {
+                    state.failure_count += 1;
+                    state.failure_count
+                });
+                warn!("[AsyncThresholdBreaker::handle_close({})] Function call timed out, {} failures so far.",
+                    self.name, failure_count);
+                if failure_count > self.threshold {
+                    self.do_trip();
+                }
+                Err(CircuitBreakerError::Timeout(String::from(&self.name)))
+            }
+        }
+    }
+
+    /// Handle the Recovering state, entered once the backoff elapses. Since
+    /// `AsyncThresholdBreaker` is constructed with a zero recovery ramp, every call is
+    /// admitted here: a success closes the breaker, a failure trips it straight back to Open.
+    async fn handle_recovering<F, Fut, R: Send, E: Error + Send>(&self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Fut + Send,
+              Fut: Future<Output = Result<R, E>> + Send
+    {
+        debug!("[AsyncThresholdBreaker::handle_recovering({})]", self.name);
+        match self.run(f).await {
+            Outcome::Success(result) => {
+                debug!("[AsyncThresholdBreaker::handle_recovering({})] Function called successfully.", self.name);
+                self.reset();
+                Ok(result)
+            },
+            Outcome::Failure(error) => {
+                warn!("[AsyncThresholdBreaker::handle_recovering({})] Still not going to open!", self.name);
+                self.trip(error)
+            },
+            Outcome::TimedOut => {
+                warn!("[AsyncThresholdBreaker::handle_recovering({})] Call timed out, still not going to open!", self.name);
+                self.do_trip();
+                Err(CircuitBreakerError::Timeout(String::from(&self.name)))
+            }
+        }
+    }
+
+    /// Resetting the failure count and setting the AsyncThresholdBreaker in close state.
+    fn reset(&self) {
+        debug!("[AsyncThresholdBreaker::reset({})]", self.name);
+        self.with_state(|state| {
+            state.failure_count = 0;
+            state.recovery.reset();
+        });
+    }
+
+    /// Setting the circuit breaker into the open state.
+    fn do_trip(&self) {
+        self.with_state(|state| state.recovery.trip());
+    }
+
+    /// Setting the circuit breaker into the open state, embedding the causing `error`.
+    fn trip<R, E: Error>(&self, error: E) -> Result<R, CircuitBreakerError<E>> {
+        self.do_trip();
+        Err(CircuitBreakerError::Tripped(String::from(&self.name), error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    enum TestError {
+        #[error("An expected failure!")]
+        ExpectedFailure
+    }
+
+    async fn success(parameter: &'static str) -> Result<&'static str, TestError> {
+        Ok(parameter)
+    }
+
+    async fn fail(should_fail: bool) -> Result<&'static str, TestError> {
+        match should_fail {
+            true => Err(TestError::ExpectedFailure),
+            false => Ok("Don't fail")
+        }
+    }
+
+    #[tokio::test]
+    async fn success_failure_trip_and_recover_cycle() {
+        let cb = AsyncThresholdBreaker::new(
+            "success_failure_trip_and_recover_cycle", Some(0), Some(Duration::new(1, 0)), None);
+        // A success on a Close breaker just succeeds.
+        assert!(cb.call(|| success("Hello")).await.is_ok());
+        // With threshold 0, a single failure trips the breaker.
+        assert!(cb.call(|| fail(true)).await.is_err());
+        assert_eq!(CircuitState::Open, cb.with_state(|state| state.recovery.status()));
+        // While Open, calls are rejected without running `f`.
+        match cb.call(|| fail(false)).await {
+            Err(CircuitBreakerError::StaysOpen(name)) => assert_eq!("success_failure_trip_and_recover_cycle", name),
+            other => panic!("Unexpected result: {:?}", other.is_ok())
+        }
+        // Once the backoff elapses, the next call is admitted (Recovering) and, succeeding,
+        // closes the breaker again.
+        tokio::time::sleep(cb.with_state(|state| state.recovery.backoff_wait())).await;
+        assert!(cb.call(|| success("recovered")).await.is_ok());
+        assert_eq!(CircuitState::Close, cb.with_state(|state| state.recovery.status()));
+    }
+
+    #[tokio::test]
+    async fn exactly_one_task_is_admitted_past_an_open_breaker() {
+        // Locks in the cc59f48 CAS fix: of many tasks racing `handle_open` the instant the
+        // backoff elapses, exactly one must win the Open -> Recovering swap and actually
+        // run `f`. The probe fails, so the winner trips the breaker straight back to Open
+        // and the rest observe `StaysOpen`.
+        let cb = Arc::new(AsyncThresholdBreaker::new(
+            "exactly_one_task_is_admitted_past_an_open_breaker", Some(0), Some(Duration::new(1, 0)), None));
+        assert!(cb.call(|| fail(true)).await.is_err());
+        assert_eq!(CircuitState::Open, cb.with_state(|state| state.recovery.status()));
+        tokio::time::sleep(cb.with_state(|state| state.recovery.backoff_wait())).await;
+
+        let admitted = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Vec::new();
+        for _ in 0..16 {
+            let cb = cb.clone();
+            let admitted = admitted.clone();
+            tasks.push(tokio::spawn(async move {
+                cb.call(|| async {
+                    admitted.fetch_add(1, Ordering::SeqCst);
+                    fail(true).await
+                }).await
+            }));
+        }
+        for task in tasks {
+            let _ = task.await.expect("task panicked");
+        }
+        assert_eq!(1, admitted.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+///
+/// Strategy used to compute how long a breaker waits in the Open state before moving on
+/// to Recovering. Applied on every trip, using the number of consecutive trips so that
+/// clients hitting the same overloaded service don't all retry on the same fixed interval,
+/// which tends to synchronize into a retry storm.
+///
+pub enum Backoff {
+    /// Always wait the same, fixed duration, regardless of how often the breaker tripped.
+    Constant(Duration),
+    /// Waits `min(base * factor^trip_count, max)`, growing the wait on every consecutive trip.
+    Exponential { base: Duration, factor: f64, max: Duration },
+    /// Like `Exponential`, but returns `d/2 + rand(0..d/2)` of the exponential value `d`,
+    /// spreading retries evenly across the upper half of the computed backoff.
+    EqualJittered { base: Duration, factor: f64, max: Duration },
+    /// Like `Exponential`, but returns `rand(0..d)` of the exponential value `d`, spreading
+    /// retries across the whole computed backoff.
+    FullJittered { base: Duration, factor: f64, max: Duration }
+}
+impl Backoff
+{
+    /// Computes the wait duration for the given number of consecutive trips (0 for the
+    /// first trip since the breaker was last closed).
+    pub fn wait(&self, consecutive_trip_count: u32) -> Duration {
+        match self {
+            Backoff::Constant(d) => *d,
+            Backoff::Exponential { base, factor, max } =>
+                Self::exponential(*base, *factor, *max, consecutive_trip_count),
+            Backoff::EqualJittered { base, factor, max } => {
+                let d = Self::exponential(*base, *factor, *max, consecutive_trip_count);
+                d / 2 + Self::jitter(d / 2)
+            },
+            Backoff::FullJittered { base, factor, max } => {
+                let d = Self::exponential(*base, *factor, *max, consecutive_trip_count);
+                Self::jitter(d)
+            }
+        }
+    }
+
+    /// `min(base * factor^trip_count, max)`.
+    fn exponential(base: Duration, factor: f64, max: Duration, trip_count: u32) -> Duration {
+        let scaled = base.as_secs_f64() * factor.powi(trip_count as i32);
+        Duration::from_secs_f64(scaled.min(max.as_secs_f64()).max(0.0))
+    }
+
+    /// A uniformly distributed duration in `0..d`.
+    fn jitter(d: Duration) -> Duration {
+        if d.is_zero() {
+            return d;
+        }
+        Duration::from_secs_f64(rand::random::<f64>() * d.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_always_returns_the_same_wait() {
+        let backoff = Backoff::Constant(Duration::new(5, 0));
+        assert_eq!(Duration::new(5, 0), backoff.wait(0));
+        assert_eq!(Duration::new(5, 0), backoff.wait(10));
+    }
+
+    #[test]
+    fn exponential_grows_and_clamps_to_max() {
+        let backoff = Backoff::Exponential {
+            base: Duration::new(1, 0), factor: 2.0, max: Duration::new(10, 0)
+        };
+        assert_eq!(Duration::new(1, 0), backoff.wait(0));
+        assert_eq!(Duration::new(2, 0), backoff.wait(1));
+        assert_eq!(Duration::new(4, 0), backoff.wait(2));
+        // 1 * 2^5 = 32, clamped to max.
+        assert_eq!(Duration::new(10, 0), backoff.wait(5));
+    }
+
+    #[test]
+    fn equal_jittered_stays_within_the_upper_half_of_the_exponential_value() {
+        let backoff = Backoff::EqualJittered {
+            base: Duration::new(1, 0), factor: 2.0, max: Duration::new(10, 0)
+        };
+        for trip_count in 0..20 {
+            let d = Backoff::exponential(Duration::new(1, 0), 2.0, Duration::new(10, 0), trip_count);
+            let wait = backoff.wait(trip_count);
+            assert!(wait >= d / 2, "{:?} should be >= {:?}", wait, d / 2);
+            assert!(wait <= d, "{:?} should be <= {:?}", wait, d);
+        }
+    }
+
+    #[test]
+    fn full_jittered_stays_within_the_exponential_value() {
+        let backoff = Backoff::FullJittered {
+            base: Duration::new(1, 0), factor: 2.0, max: Duration::new(10, 0)
+        };
+        for trip_count in 0..20 {
+            let d = Backoff::exponential(Duration::new(1, 0), 2.0, Duration::new(10, 0), trip_count);
+            let wait = backoff.wait(trip_count);
+            assert!(wait <= d, "{:?} should be <= {:?}", wait, d);
+        }
+    }
+
+    #[test]
+    fn jitter_of_zero_is_zero() {
+        assert_eq!(Duration::new(0, 0), Backoff::jitter(Duration::new(0, 0)));
+    }
+}
@@ -18,6 +18,11 @@ pub enum CircuitBreakerError<E: Error> {
     /// The name of the circuit breaker can be extracted from this error. It is returned,
     /// if the circuit breaker stays open.
     #[error("The circuit breaker '{0}' will stay open.")]
-    StaysOpen(String)
+    StaysOpen(String),
+    /// The name of the circuit breaker can be extracted from this error. It is returned,
+    /// if the wrapped call exceeded the configured `call_timeout`. Counts as a failure
+    /// towards the trip threshold, just like an `Err` returned by the wrapped call.
+    #[error("The circuit breaker '{0}' timed out waiting for the wrapped call.")]
+    Timeout(String)
 }
 
@@ -2,10 +2,20 @@ use std::error::Error;
 
 mod error;
 mod state;
+mod recovery;
 mod threshold;
+mod rate;
+mod backoff;
+#[cfg(feature = "async")]
+mod asynchronous;
 
 pub use error::CircuitBreakerError;
+pub use recovery::TransitionCallback;
 pub use threshold::ThresholdBreaker;
+pub use rate::RateBreaker;
+pub use backoff::Backoff;
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncCircuitBreaker, AsyncThresholdBreaker};
 
 //pub type Callback = FnOnce() -> Result<_, E = Error>;
 
@@ -0,0 +1,279 @@
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::VecDeque;
+use log::{debug, warn, trace};
+
+use crate::state::CircuitState;
+use crate::recovery::RecoveryState;
+use crate::backoff::Backoff;
+use crate::CircuitBreaker;
+use crate::CircuitBreakerError;
+
+/// A single per-second bucket, counting the successes and failures observed during that second.
+struct Bucket {
+    /// The second (as seconds since the UNIX epoch) this bucket accounts for.
+    second: u64,
+    /// Number of successful calls recorded in this bucket.
+    successes: usize,
+    /// Number of failed calls recorded in this bucket.
+    failures: usize
+}
+
+///
+/// The RateBreaker trips based on the failure rate observed over a rolling time window,
+/// instead of a number of consecutive failures like the ThresholdBreaker.
+/// This better reflects services which fail intermittently, e.g. a database under load,
+/// which fails a fraction of the requests while the rest still succeed.
+///
+/// The Open-state gating, backoff and transition hook are shared with `ThresholdBreaker`
+/// via `RecoveryState`; only the decision of *when* to trip (rolling error rate here,
+/// consecutive failures there) is specific to this breaker. `RateBreaker` is constructed
+/// with a zero recovery ramp, so Recovering behaves like a classic single-probe HalfOpen:
+/// the first call after the backoff elapses is admitted, a success closes the breaker, a
+/// failure trips it straight back to Open.
+///
+pub struct RateBreaker {
+    /// The name of this breaker to better identify it in the logs.
+    name: String,
+    /// The shared Open/Recovering/trip/reset state machine.
+    recovery: RecoveryState,
+    /// The rolling window of per-second buckets, oldest first.
+    buckets: VecDeque<Bucket>,
+    /// The size of the rolling window. Buckets older than now - window are evicted.
+    window: Duration,
+    /// The minimum number of calls within the window, before the error rate is evaluated.
+    min_calls: usize,
+    /// The failure rate (failures / (successes + failures)), which trips the breaker.
+    error_rate: f64
+}
+impl <F, R, E: Error> CircuitBreaker <F, R, E> for RateBreaker
+    where F: FnOnce() -> Result<R, E>
+{
+    /// Try to execute and record the outcome in the rolling window.
+    /// Any error returned by the embedded function will be propagated to the callee.
+    /// In addition CircuteBreakerError might be thrown.
+    fn call(&mut self, f: F) -> Result<R, CircuitBreakerError<E>> {
+        debug!("[RateBreaker::execute({})]", self.name);
+        match self.recovery.status() {
+            CircuitState::Open => self.handle_open(f),
+            CircuitState::Close => self.handle_close(f),
+            CircuitState::Recovering => self.handle_recovering(f),
+            // RecoveryState never hands this breaker a bare HalfOpen; treat it like Open
+            // defensively, since `try_leave_open` only ever moves to Recovering.
+            CircuitState::HalfOpen => self.handle_open(f)
+        }
+    }
+}
+impl RateBreaker
+{
+    /// Creates a new RateBreaker instance.
+    /// @param name The name of the circuit breaker, for logging/debugging purposes.
+    /// @param window The size of the rolling window, over which the failure rate is computed.
+    /// @param min_calls The minimum number of calls within the window, before the rate is evaluated.
+    /// @param error_rate The failure rate (0.0 - 1.0), which trips the circuit breaker.
+    /// @param timeout The time before the circuit breaker isn't changing back to the close status.
+    pub fn new(
+        name: &str,
+        window: Option<Duration>,
+        min_calls: Option<usize>,
+        error_rate: Option<f64>,
+        timeout: Option<Duration>) -> RateBreaker
+    {
+        debug!("[RateBreaker::new({})]", name);
+
+        let timeout = timeout.unwrap_or(Duration::new(5, 0));
+        RateBreaker {
+            name: String::from(name),
+            recovery: RecoveryState::new(name, Backoff::Constant(timeout), Duration::ZERO),
+            buckets: VecDeque::new(),
+            window: window.unwrap_or(Duration::new(60, 0)),
+            min_calls: min_calls.unwrap_or(10),
+            error_rate: error_rate.unwrap_or(0.5)
+        }
+    }
+
+    /// Handle the case if the circuit is open (tripped).
+    /// It just checks, if the time is up. If not, it just returns an CircuitBreakerError.
+    /// Moves to Recovering and calling execute otherwise.
+    fn handle_open<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E>
+    {
+        debug!("[RateBreaker::handle_open({})]", self.name);
+        if self.recovery.try_leave_open(SystemTime::now()) {
+            self.handle_recovering(f)
+        }
+        else {
+            debug!("[RateBreaker::handle_open({})] stays open!", self.name);
+            Err(CircuitBreakerError::StaysOpen(String::from(&self.name)))
+        }
+    }
+
+    /// Handle the case, if the circuit is (still) closed.
+    /// Records the outcome in the rolling window and trips, once the window contains at
+    /// least `min_calls` calls and the failure rate reached the configured `error_rate`.
+    fn handle_close<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E>
+    {
+        debug!("[RateBreaker::handle_close({})]", self.name);
+        match f() {
+            Ok(result) => {
+                trace!("[RateBreaker::handle_close({})] Function called successfully.", self.name);
+                self.record(true);
+                Ok(result)
+            },
+            Err(error) => {
+                self.record(false);
+                let (successes, failures) = self.totals();
+                let total = successes + failures;
+                warn!("[RateBreaker::handle_close({})] Function call failed. {} of {} calls failed in the window.",
+                    self.name, failures, total);
+                if total >= self.min_calls && (failures as f64 / total as f64) >= self.error_rate {
+                    return self.trip(error);
+                }
+                Err(CircuitBreakerError::Failed(error))
+            }
+        }
+    }
+
+    /// Handle the Recovering state, entered once the backoff elapses. Since `RateBreaker`
+    /// is constructed with a zero recovery ramp, every call is admitted here: a success
+    /// closes the breaker, a failure trips it straight back to Open.
+    fn handle_recovering<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E>
+    {
+        debug!("[RateBreaker::handle_recovering({})]", self.name);
+        match f() {
+            Ok(result) => {
+                debug!("[RateBreaker::handle_recovering({})] Function called successfully.", self.name);
+                self.reset();
+                Ok(result)
+            }
+            Err(error) => {
+                warn!("[RateBreaker::handle_recovering({})] Still not going to open!", self.name);
+                self.trip(error)
+            }
+        }
+    }
+
+    /// Records a single call outcome in the bucket for the current second, evicting
+    /// any buckets, which fell out of the window.
+    fn record(&mut self, success: bool) {
+        let second = Self::current_second();
+        self.evict(second);
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.second == second => {
+                if success { bucket.successes += 1; } else { bucket.failures += 1; }
+            },
+            _ => {
+                let mut bucket = Bucket { second, successes: 0, failures: 0 };
+                if success { bucket.successes += 1; } else { bucket.failures += 1; }
+                self.buckets.push_back(bucket);
+            }
+        }
+    }
+
+    /// Evicts all buckets, which are older than the configured window.
+    fn evict(&mut self, now: u64) {
+        let window_secs = self.window.as_secs();
+        while let Some(bucket) = self.buckets.front() {
+            if now.saturating_sub(bucket.second) > window_secs {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Sums the successes and failures of the remaining buckets in the window.
+    fn totals(&self) -> (usize, usize) {
+        self.buckets.iter().fold((0, 0), |(successes, failures), bucket| {
+            (successes + bucket.successes, failures + bucket.failures)
+        })
+    }
+
+    /// The current time as seconds since the UNIX epoch, used to bucket call outcomes.
+    fn current_second() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("SystemTime before UNIX_EPOCH")
+            .as_secs()
+    }
+
+    /// Resetting the rolling window and setting the RateBreaker in close state.
+    fn reset(&mut self) {
+        debug!("[RateBreaker::reset({})]", self.name);
+        self.buckets.clear();
+        self.recovery.reset();
+    }
+
+    /// Setting the circuit breaker into the open state.
+    fn trip<R, E: Error>(&mut self, error: E) -> Result<R, CircuitBreakerError<E>> {
+        self.buckets.clear();
+        self.recovery.trip();
+        Err(CircuitBreakerError::Tripped(String::from(&self.name), error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use log::debug;
+    use std::time::Duration;
+    use thiserror::Error;
+
+    #[derive(Error, Debug)]
+    enum TestError {
+        #[error("An expected failure!")]
+        ExpectedFailure
+    }
+
+    fn fail(should_fail: bool) -> Result<&'static str, TestError> {
+        match should_fail {
+            true => Err(TestError::ExpectedFailure),
+            false => Ok("Don't fail")
+        }
+    }
+
+    #[test]
+    fn stays_closed_below_min_calls() {
+        let mut cb = RateBreaker::new("stays_closed_below_min_calls", None, Some(10), Some(0.5), None);
+        // A single failure must not trip the breaker, as min_calls isn't reached.
+        match cb.call(|| fail(true)) {
+            Ok(_) => panic!("Unexpected success!"),
+            Err(_) => assert_eq!(CircuitState::Close, cb.recovery.status())
+        }
+    }
+
+    #[test]
+    fn trips_on_high_error_rate() {
+        let mut cb = RateBreaker::new(
+            "trips_on_high_error_rate", Some(Duration::new(60, 0)), Some(4), Some(0.5), Some(Duration::new(1, 0)));
+        assert!(cb.call(|| fail(false)).is_ok());
+        assert!(cb.call(|| fail(false)).is_ok());
+        assert!(cb.call(|| fail(true)).is_err());
+        // The fourth call pushes the window to 50% failures, which trips it.
+        match cb.call(|| fail(true)) {
+            Ok(_) => panic!("Unexpected success!"),
+            Err(_) => assert_eq!(CircuitState::Open, cb.recovery.status())
+        }
+    }
+
+    #[test]
+    fn recovers_after_timeout() {
+        let mut cb = RateBreaker::new(
+            "recovers_after_timeout", Some(Duration::new(60, 0)), Some(2), Some(0.5), Some(Duration::new(1, 0)));
+        assert!(cb.call(|| fail(true)).is_ok() == false);
+        match cb.call(|| fail(true)) {
+            Ok(_) => panic!("Unexpected success!"),
+            Err(_) => assert_eq!(CircuitState::Open, cb.recovery.status())
+        }
+        sleep(cb.recovery.backoff_wait());
+        match cb.call(|| fail(false)) {
+            Ok(_) => {
+                debug!("[recovers_after_timeout] recovered.");
+                assert_eq!(CircuitState::Close, cb.recovery.status())
+            },
+            Err(err) => panic!("Unexpected error: {}", err)
+        }
+    }
+}
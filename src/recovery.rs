@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime};
+use log::error;
+
+use crate::backoff::Backoff;
+use crate::state::CircuitState;
+
+/// Callback invoked by [`RecoveryState::on_transition`] with the breaker's name, the old
+/// state and the new state.
+pub type TransitionCallback = Box<dyn FnMut(&str, CircuitState, CircuitState)>;
+
+///
+/// The Open/Recovering/trip/reset state machine shared by every breaker in this crate
+/// (`ThresholdBreaker`, `RateBreaker`, `AsyncThresholdBreaker`). Each breaker still decides
+/// *when* to call `trip`/`reset` (that depends on whether it counts consecutive failures or
+/// a rolling error rate), but the Open-state gating, the backoff before leaving Open, the
+/// traffic ramp while Recovering and the transition hook live here once instead of being
+/// reimplemented by each breaker.
+///
+/// A zero `recovery_duration` makes `allowed_ratio` reach 1.0 the instant Recovering is
+/// entered, giving classic single-probe HalfOpen semantics: the first call after the
+/// backoff elapses is admitted, a success closes the breaker, a failure trips it straight
+/// back to Open. A non-zero `recovery_duration` instead ramps admission up linearly, as
+/// used by `ThresholdBreaker`.
+///
+pub struct RecoveryState {
+    name: String,
+    status: CircuitState,
+    backoff: Backoff,
+    consecutive_trip_count: u32,
+    time_of_tripping: Option<SystemTime>,
+    /// The wait drawn from `backoff` at the last trip.
+    open_wait: Duration,
+    recovery_duration: Duration,
+    recovery_start: Option<SystemTime>,
+    on_transition: Option<TransitionCallback>
+}
+impl RecoveryState
+{
+    pub fn new(name: &str, backoff: Backoff, recovery_duration: Duration) -> RecoveryState {
+        RecoveryState {
+            name: String::from(name),
+            status: CircuitState::Close,
+            backoff,
+            consecutive_trip_count: 0,
+            time_of_tripping: None,
+            open_wait: Duration::ZERO,
+            recovery_duration,
+            recovery_start: None,
+            on_transition: None
+        }
+    }
+
+    /// The current state of the breaker this `RecoveryState` backs.
+    pub fn status(&self) -> CircuitState {
+        self.status
+    }
+
+    /// The wait this state machine currently enforces before leaving Open, i.e. the
+    /// `backoff.wait(..)` drawn once at the last trip. Exposed mainly for tests that need to
+    /// sleep past the current backoff.
+    pub fn backoff_wait(&self) -> Duration {
+        self.open_wait
+    }
+
+    /// How long the linear traffic ramp in the Recovering state takes to reach 100%.
+    pub fn recovery_duration(&self) -> Duration {
+        self.recovery_duration
+    }
+
+    /// Registers a callback, invoked with this breaker's name, the old state and the new
+    /// state whenever `trip()`, `reset()` or an Open to Recovering move actually changes
+    /// the state. Lets users wire in metrics/alerting (increment a counter, fire a
+    /// webhook, emit a tracing span) without forking the state machine. The hook fires
+    /// exactly once per actual state change; pass `None` to remove a previously set hook.
+    pub fn on_transition(&mut self, callback: Option<TransitionCallback>) {
+        self.on_transition = callback;
+    }
+
+    /// Moves to `new` state, firing the `on_transition` hook if, and only if, this
+    /// actually changes the state.
+    fn set_status(&mut self, new: CircuitState) {
+        let old = self.status;
+        if old != new {
+            self.status = new;
+            if let Some(callback) = &mut self.on_transition {
+                let name = self.name.clone();
+                callback(&name, old, new);
+            }
+        }
+    }
+
+    /// Moves to Open, drawing the wait from `backoff` with the number of earlier consecutive
+    /// trips (0 for the first trip since the last reset), then bumping that count.
+    pub fn trip(&mut self) {
+        error!("[RecoveryState::trip({})]", self.name);
+        self.time_of_tripping = Some(SystemTime::now());
+        self.open_wait = self.backoff.wait(self.consecutive_trip_count);
+        self.consecutive_trip_count += 1;
+        self.recovery_start = None;
+        self.set_status(CircuitState::Open);
+    }
+
+    /// Clears all trip bookkeeping and moves back to Close.
+    pub fn reset(&mut self) {
+        self.consecutive_trip_count = 0;
+        self.time_of_tripping = None;
+        self.recovery_start = None;
+        self.set_status(CircuitState::Close);
+    }
+
+    /// Call while Open. If the wait drawn at the last trip has elapsed, atomically moves to Recovering and
+    /// returns `true` for exactly the one caller that won the swap; every other caller,
+    /// whether because the wait hasn't elapsed yet or because another caller already won,
+    /// gets `false`. Reading `time_of_tripping` and writing the new status inside this one
+    /// call keeps the transition race-free when a `RecoveryState` is shared behind a lock,
+    /// instead of each breaker re-deriving its own (possibly racy) locking discipline.
+    pub fn try_leave_open(&mut self, now: SystemTime) -> bool {
+        let time_of_tripping = self.time_of_tripping.unwrap_or(now);
+        let wait = self.open_wait;
+        if self.status == CircuitState::Open && now > time_of_tripping + wait {
+            self.set_status(CircuitState::Recovering);
+            self.recovery_start = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The fraction of calls to admit while Recovering: a linear ramp from 0 to 1 over
+    /// `recovery_duration` (clamped to `[0, 1]`), reaching 1.0 immediately if
+    /// `recovery_duration` is zero.
+    pub fn allowed_ratio(&self, now: SystemTime) -> f64 {
+        if self.recovery_duration.is_zero() {
+            return 1.0;
+        }
+        let recovery_start = self.recovery_start.unwrap_or(now);
+        let elapsed = now.duration_since(recovery_start).unwrap_or(Duration::new(0, 0));
+        (elapsed.as_secs_f64() / self.recovery_duration.as_secs_f64()).clamp(0.0, 1.0)
+    }
+}
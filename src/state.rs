@@ -1,9 +1,12 @@
 use std::fmt::Debug;
 
 ///
-/// The three states of the CircuitBreaker.
+/// The four states of the CircuitBreaker.
 ///
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CircuitState {
-    Open, Close, HalfOpen
+    Open, Close, HalfOpen,
+    /// Entered after the open timeout elapses. Only a growing fraction of calls is
+    /// admitted while the circuit ramps traffic back up to the recovered service.
+    Recovering
 }
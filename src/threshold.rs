@@ -1,41 +1,66 @@
 use std::error::Error;
 use std::time::{Duration, SystemTime};
-use log::{debug, warn, error, trace};
+use std::sync::mpsc;
+use std::thread;
+use log::{debug, warn, trace};
 
 use crate::state::CircuitState;
+use crate::recovery::RecoveryState;
 use crate::CircuitBreaker;
 use crate::CircuitBreakerError;
+use crate::backoff::Backoff;
+use crate::recovery::TransitionCallback;
+
+/// The outcome of running a wrapped call through `ThresholdBreaker::run`, distinguishing
+/// a timeout (no `E` value is available) from a regular `Err(E)`.
+enum Outcome<R, E> {
+    Success(R),
+    Failure(E),
+    TimedOut
+}
 
 ///
 /// The CircuitBreaker is implementing the protection pattern for distributed services.
 /// It is basically used in my case to protect the service from database failures.
 ///
+/// `F`, `R` and `E` are bounded by `Send + 'static` unconditionally, even when
+/// `call_timeout` is `None`: only `run`'s timeout path actually needs to join the call from
+/// a worker thread, but the bound lives on the shared `CircuitBreaker` impl, so callers
+/// pay for it regardless of whether they ever configure a `call_timeout`. This is a
+/// deliberate tradeoff kept for now, since splitting it out would mean either a second,
+/// non-'static `ThresholdBreaker` variant or a generic parameter threading the bound
+/// through the whole impl; revisit if a non-'static, non-`Send` use case shows up.
+///
 pub struct ThresholdBreaker {
     /// The name of this breaker to better identify it in the locks.
     name: String,
     /// The current count of failures. Will be resetted by success.
     failure_count: usize,
-    /// The current state of the circuite breaker
-    status: CircuitState,
+    /// The shared Open/Recovering/trip/reset state machine.
+    recovery: RecoveryState,
     /// number of exapted failures
     threshold: usize,
-    /// timeout to be waited before we try to execute again.
-    timeout: Duration,
-    /// The point in time, when the circuit was opened.
-    time_of_tripping: Option<SystemTime>
+    /// Optional deadline for the wrapped call. A call running longer than this is recorded
+    /// as a failure towards the trip threshold and surfaced as `CircuitBreakerError::Timeout`.
+    call_timeout: Option<Duration>
 }
-impl <F, R, E: Error> CircuitBreaker <F, R, E> for ThresholdBreaker
-    where F: FnOnce() -> Result<R, E>
+impl <F, R: Send + 'static, E: Error + Send + 'static> CircuitBreaker <F, R, E> for ThresholdBreaker
+    where F: FnOnce() -> Result<R, E> + Send + 'static
 {
     /// Try to execute and count the failures here.
     /// Any error returned by the embedded function will be propagated to the callee.
     /// In addition CircuteBreakerError might be thrown.
+    /// `F`, `R` and `E` need to be `Send + 'static`, so the call can be bounded by
+    /// `call_timeout` on a worker thread.
     fn call(&mut self, f: F) -> Result<R, CircuitBreakerError<E>> {
         debug!("[CircuitBreaker::execute({})]", self.name);
-        match self.status {
+        match self.recovery.status() {
             CircuitState::Open => self.handle_open(f),
             CircuitState::Close => self.handle_close(f),
-            CircuitState::HalfOpen => self.handle_half_open(f)
+            CircuitState::Recovering => self.handle_recovering(f),
+            // RecoveryState never hands this breaker a bare HalfOpen; treat it like Open
+            // defensively, since `try_leave_open` only ever moves to Recovering.
+            CircuitState::HalfOpen => self.handle_open(f)
         }
     }
 }
@@ -46,35 +71,79 @@ impl ThresholdBreaker
     /// @param function The function, which will be wrapped by the circuit breaker.
     /// @param threshold The number of consecutive failures, which trip the circuit breaker.
     /// @param timeout The time before the circuit breaker isn't changing back to the close status.
+    /// Only used to build the default `Backoff::Constant(timeout)` when `backoff` is `None`;
+    /// ignored if `backoff` is given explicitly.
+    /// @param backoff The strategy used to grow that wait on repeated, consecutive trips.
+    /// Defaults to waiting `timeout` every time, i.e. no backoff.
+    /// @param recovery_duration How long the linear traffic ramp in the Recovering state
+    /// takes to go from admitting no calls to admitting all of them.
+    /// @param call_timeout The deadline for the wrapped call. `None` disables it, in which
+    /// case calls can run arbitrarily long.
     pub fn new(
         name: &str,
         threshold: Option<usize>,
-        timeout: Option<Duration>) -> ThresholdBreaker
+        timeout: Option<Duration>,
+        backoff: Option<Backoff>,
+        recovery_duration: Option<Duration>,
+        call_timeout: Option<Duration>) -> ThresholdBreaker
     {
         debug!("[CircuitBreaker::new({})]", name);
 
+        let timeout = timeout.unwrap_or(Duration::new(5, 0));
+        let backoff = backoff.unwrap_or(Backoff::Constant(timeout));
+        let recovery_duration = recovery_duration.unwrap_or(Duration::new(10, 0));
         ThresholdBreaker {
             name: String::from(name),
             failure_count: 0,
-            status: CircuitState::Close,
-            threshold: if let Some(t) = threshold { t } else { 5 },
-            timeout: if let Some(d) = timeout { d } else { Duration::new(5, 0) },
-            time_of_tripping: None
+            recovery: RecoveryState::new(name, backoff, recovery_duration),
+            threshold: threshold.unwrap_or(5),
+            call_timeout
+        }
+    }
+
+    /// Runs `f`, bounding its execution by `call_timeout` if one is configured. Since the
+    /// call has to be joined from a worker thread to be bounded, `F`, `R` and `E` must be
+    /// `Send + 'static`.
+    fn run<F, R: Send + 'static, E: Send + 'static>(&self, f: F) -> Outcome<R, E>
+        where F: FnOnce() -> Result<R, E> + Send + 'static
+    {
+        let timeout = match self.call_timeout {
+            Some(timeout) => timeout,
+            None => return match f() {
+                Ok(result) => Outcome::Success(result),
+                Err(error) => Outcome::Failure(error)
+            }
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver may already be gone, if we gave up waiting for it.
+            let _ = sender.send(f());
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(result)) => Outcome::Success(result),
+            Ok(Err(error)) => Outcome::Failure(error),
+            Err(_) => Outcome::TimedOut
         }
     }
 
+    /// Registers a callback, invoked with this breaker's name, the old state and the new
+    /// state whenever `trip()`, `reset()` or an Open to Recovering move actually changes
+    /// the state. Lets users wire in metrics/alerting (increment a counter, fire a
+    /// webhook, emit a tracing span) without forking the state machine. The hook fires
+    /// exactly once per actual state change; pass `None` to remove a previously set hook.
+    pub fn on_transition(&mut self, callback: Option<TransitionCallback>) {
+        self.recovery.on_transition(callback);
+    }
+
     /// Handle the case if the circuit is open (tripped).
     /// It just checks, if the time is up. If not, it just returns an CircuitBreakerError.
-    /// Moves to HalfOpen and calling execute otherwise.
-    fn handle_open<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
-        where F: FnOnce() -> Result<R, E>
+    /// Moves to Recovering and calling execute otherwise.
+    fn handle_open<F, R: Send + 'static, E: Error + Send + 'static>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E> + Send + 'static
     {
         debug!("[CircuitBreaker::handle_open({})]", self.name);
-        let now = SystemTime::now();
-        let time_of_tripping = if let Some(tot) = self.time_of_tripping { tot } else { now };
-        if now > time_of_tripping + self.timeout {
-            self.status = CircuitState::HalfOpen;
-            self.call(f)
+        if self.recovery.try_leave_open(SystemTime::now()) {
+            self.handle_recovering(f)
         }
         else {
             debug!("[CircuitBreaker::handle_open({})] stays open!", self.name);
@@ -82,21 +151,56 @@ impl ThresholdBreaker
         }
     }
 
+    /// Handle the Recovering state. Entered once the open timeout elapses, it ramps
+    /// traffic back up linearly over `recovery_duration`: a call is admitted with
+    /// probability `(now - recovery_start) / recovery_duration` (clamped to [0,1]), and
+    /// rejected with a `StaysOpen` error otherwise. An admitted call that fails trips the
+    /// breaker straight back to Open; once the ramp reaches 100% and a call succeeds, the
+    /// breaker closes.
+    fn handle_recovering<F, R: Send + 'static, E: Error + Send + 'static>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E> + Send + 'static
+    {
+        debug!("[CircuitBreaker::handle_recovering({})]", self.name);
+        let allowed_ratio = self.recovery.allowed_ratio(SystemTime::now());
+        if allowed_ratio < 1.0 && rand::random::<f64>() >= allowed_ratio {
+            debug!("[CircuitBreaker::handle_recovering({})] not admitted, ratio={}", self.name, allowed_ratio);
+            return Err(CircuitBreakerError::StaysOpen(String::from(&self.name)));
+        }
+        match self.run(f) {
+            Outcome::Success(result) => {
+                if allowed_ratio >= 1.0 {
+                    trace!("[CircuitBreaker::handle_recovering({})] ramp complete, closing.", self.name);
+                    self.reset();
+                }
+                Ok(result)
+            },
+            Outcome::Failure(error) => {
+                warn!("[CircuitBreaker::handle_recovering({})] admitted call failed, re-opening.", self.name);
+                self.trip(error)
+            },
+            Outcome::TimedOut => {
+                warn!("[CircuitBreaker::handle_recovering({})] admitted call timed out, re-opening.", self.name);
+                self.do_trip();
+                Err(CircuitBreakerError::Timeout(String::from(&self.name)))
+            }
+        }
+    }
+
     /// Handle the case, if the circuit is (still) closed.
     /// In this case it tries to execute the function with the provided parameters.
     /// If this fails, it will increase the failure counter, if the threshold reached,
     /// it will trip().
-    fn handle_close<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
-        where F: FnOnce() -> Result<R, E>
+    fn handle_close<F, R: Send + 'static, E: Error + Send + 'static>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
+        where F: FnOnce() -> Result<R, E> + Send + 'static
     {
         debug!("[CircuitBreaker::handle_close({})]", self.name);
-        match f() {
-            Ok(result) => {
+        match self.run(f) {
+            Outcome::Success(result) => {
                 trace!("[CircuitBreaker::handle_close({})] Function called succssfully.", self.name);
                 self.reset();
                 Ok(result)
             },
-            Err(error) => {
+            Outcome::Failure(error) => {
                 self.failure_count += 1;
                 warn!("[CircuitBreaker::handle_close({})] Function call failed {} times.",
                     self.name, self.failure_count);
@@ -104,26 +208,15 @@ impl ThresholdBreaker
                     return self.trip(error);
                 }
                 Err(CircuitBreakerError::Failed(error))
-            }
-        }
-    }
-
-    /// Handle the HalfOpen state. This is the state, after a Open state.
-    /// It executes the function with the provided parameters. If this is successful,
-    /// it goes to the close state. It trip() again otherwise.
-    fn handle_half_open<F, R, E: Error>(&mut self, f: F) -> Result<R, CircuitBreakerError<E>>
-        where F: FnOnce() -> Result<R, E>
-    {
-        debug!("[CircuitBreaker::handle_half_open({})]", self.name);
-        match f() {
-            Ok(result) => {
-                debug!("[CircuitBreaker::handle_half_open({})] Function called successfully.", self.name);
-                self.reset();
-                Ok(result)
-            }
-            Err(error) => {
-                warn!("[CircuitBreaker::handle_half_open({})] Still not going to open!", self.name);
-                return self.trip(error)
+            },
+            Outcome::TimedOut => {
+                self.failure_count += 1;
+                warn!("[CircuitBreaker::handle_close({})] Function call timed out, {} failures so far.",
+                    self.name, self.failure_count);
+                if self.failure_count > self.threshold {
+                    self.do_trip();
+                }
+                Err(CircuitBreakerError::Timeout(String::from(&self.name)))
             }
         }
     }
@@ -132,15 +225,17 @@ impl ThresholdBreaker
     fn reset(&mut self) {
         debug!("[CircuitBreaker::reset({})]", self.name);
         self.failure_count = 0;
-        self.status = CircuitState::Close;
-        self.time_of_tripping = None;
+        self.recovery.reset();
     }
 
     /// Setting the circuit breaker into the open state.
+    fn do_trip(&mut self) {
+        self.recovery.trip();
+    }
+
+    /// Setting the circuit breaker into the open state, embedding the causing `error`.
     fn trip<R, E: Error>(&mut self, error: E) -> Result<R, CircuitBreakerError<E>> {
-        error!("[CircuitBreaker::trip({})]", self.name);
-        self.status = CircuitState::Open;
-        self.time_of_tripping = Some(SystemTime::now());
+        self.do_trip();
         Err(CircuitBreakerError::Tripped(String::from(&self.name), error))
     }
 }
@@ -149,7 +244,8 @@ impl ThresholdBreaker
 mod tests {
     use super::*;
     use std::thread::sleep;
-    use log::{debug, error};
+    use std::sync::{Arc, Mutex};
+    use log::debug;
     use std::time::Duration;
     use thiserror::Error;
 
@@ -173,11 +269,11 @@ mod tests {
 
     #[test]
     fn successful_execute() {
-        let mut cb = ThresholdBreaker::new("successful_execute", None, None);
+        let mut cb = ThresholdBreaker::new("successful_execute", None, None, None, None, None);
         match cb.call(|| success("Hello")) {
             Ok(msg) => {
                 assert_eq!("Hello", msg);
-                assert_eq!(CircuitState::Close, cb.status);
+                assert_eq!(CircuitState::Close, cb.recovery.status());
             },
             Err(err) => panic!("Unexpected failure: {}!", err)
         }
@@ -189,42 +285,104 @@ mod tests {
 
     #[test]
     fn unsuccessful_execute() {
-        let mut cb = ThresholdBreaker::new("unsuccessful_execute", None, None);
+        let mut cb = ThresholdBreaker::new("unsuccessful_execute", None, None, None, None, None);
         match cb.call(|| fail(true)) {
             Ok(_) => panic!("Unexpected successful execution!"),
             Err(error) => debug!("Expected error: {}", error)
         }
     }
 
+    #[test]
+    fn on_transition_fires_once_per_state_change() {
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = transitions.clone();
+        let mut cb = ThresholdBreaker::new("on_transition_fires_once_per_state_change", Some(0), None, None, None, None);
+        cb.on_transition(Some(Box::new(move |name, old, new| {
+            recorded.lock().unwrap().push((String::from(name), old, new));
+        })));
+        // A successful call on an already-Close breaker must not fire the hook.
+        assert!(cb.call(|| success("no-op")).is_ok());
+        assert!(transitions.lock().unwrap().is_empty());
+        // The first failure trips it with threshold 0, firing Close -> Open exactly once.
+        assert!(cb.call(|| fail(true)).is_err());
+        assert_eq!(
+            vec![(String::from("on_transition_fires_once_per_state_change"), CircuitState::Close, CircuitState::Open)],
+            *transitions.lock().unwrap());
+    }
+
     #[test]
     fn recover_execute() {
-        let mut cb = ThresholdBreaker::new("recover_execute", Some(1), Some(Duration::new(1, 0)));
+        // A zero recovery_duration ramps to 100% immediately, so a single call after the
+        // timeout closes the breaker again, as before the Recovering state was introduced.
+        let mut cb = ThresholdBreaker::new(
+            "recover_execute", Some(1), Some(Duration::new(1, 0)), None, Some(Duration::new(0, 0)), None);
         // Everything is fine
         match cb.call(|| fail(false)) {
-            Ok(_) => assert_eq!(CircuitState::Close, cb.status),
+            Ok(_) => assert_eq!(CircuitState::Close, cb.recovery.status()),
             Err(err) => panic!("Unexpected error: {}", err)
         }
         // One failure is no failure!
         match cb.call(|| fail(true)) {
             Ok(_) => panic!("Unexpected success!"),
-            Err(_) => assert_eq!(CircuitState::Close, cb.status)
+            Err(_) => assert_eq!(CircuitState::Close, cb.recovery.status())
         }
         // Now the threshold steps in!
         match cb.call(|| fail(true)) {
             Ok(_) => panic!("Unexpected success!"),
-            Err(_) => assert_eq!(CircuitState::Open, cb.status)
+            Err(_) => assert_eq!(CircuitState::Open, cb.recovery.status())
         }
         // Still in the within the timeout period! The successful function is not even called.
         for _i in 1..10 {
             match cb.call(|| fail(false)) {
                 Ok(_) => panic!("Unexpected success!"),
-                Err(_) => assert_eq!(CircuitState::Open, cb.status)
+                Err(_) => assert_eq!(CircuitState::Open, cb.recovery.status())
             }
         }
-        sleep(cb.timeout);
+        sleep(cb.recovery.backoff_wait());
         match cb.call(|| fail(false)) {
-            Ok(_) => assert_eq!(CircuitState::Close, cb.status),
+            Ok(_) => assert_eq!(CircuitState::Close, cb.recovery.status()),
+            Err(err) => panic!("Unexpected error: {}", err)
+        }
+    }
+
+    #[test]
+    fn ramps_up_traffic_while_recovering() {
+        let mut cb = ThresholdBreaker::new(
+            "ramps_up_traffic_while_recovering",
+            Some(0), Some(Duration::new(1, 0)), None, Some(Duration::new(1, 0)), None);
+        // Trip it.
+        match cb.call(|| fail(true)) {
+            Ok(_) => panic!("Unexpected success!"),
+            Err(_) => assert_eq!(CircuitState::Open, cb.recovery.status())
+        }
+        sleep(cb.recovery.backoff_wait());
+        // Right at the start of the ramp, calls are practically never admitted.
+        match cb.call(|| fail(false)) {
+            Ok(_) => (),
+            Err(_) => assert_eq!(CircuitState::Recovering, cb.recovery.status())
+        }
+        // Once the recovery_duration has fully elapsed, the ramp is complete and a
+        // successful call closes the breaker again.
+        sleep(cb.recovery.recovery_duration());
+        match cb.call(|| fail(false)) {
+            Ok(_) => assert_eq!(CircuitState::Close, cb.recovery.status()),
+            Err(err) => panic!("Unexpected error: {}", err)
+        }
+    }
+
+    #[test]
+    fn slow_call_counts_as_a_failure() {
+        let mut cb = ThresholdBreaker::new(
+            "slow_call_counts_as_a_failure", Some(0), None, None, None, Some(Duration::new(0, 1)));
+        match cb.call(|| {
+            sleep(Duration::new(0, 100_000_000));
+            success("too slow")
+        }) {
+            Ok(_) => panic!("Unexpected success!"),
+            Err(CircuitBreakerError::Timeout(name)) => assert_eq!("slow_call_counts_as_a_failure", name),
             Err(err) => panic!("Unexpected error: {}", err)
         }
+        // threshold 0 means a single timeout already trips the breaker.
+        assert_eq!(CircuitState::Open, cb.recovery.status());
     }
 }